@@ -1,12 +1,14 @@
 extern crate serial;
 extern crate serial_core;
 
+use std::collections::HashMap;
 use std::io;
 use std::io::{Read, Write, BufRead, BufReader};
 use std::result::Result;
 use std::convert::From;
+use std::time::Duration;
 
-enum BootloaderCommand {
+pub enum BootloaderCommand {
     VerifyChecksum,
     GetFlashSize,
     GetAppStatus,
@@ -40,115 +42,303 @@ impl Into<u8> for BootloaderCommand {
     }
 }
 
-trait Bootloader: Read + Write + Sized {
-    fn transmit(&mut self, tx_data: &[u8], response: bool) -> Result<Vec<u8>, Error> {
-        self.write_all(tx_data)?;
+fn checksum(data: &[u8], checksum_type: &ChecksumType) -> u16 {
+    match *checksum_type {
+        ChecksumType::Sum => {
+            let checksum: u16 = data.iter().fold(0u16, |a, b| a + (*b as u16));
+            1 + !checksum
+        }
+        ChecksumType::Crc => {
+            let mut crc: u16 = 0xFFFF;
+            for byte in data {
+                let mut data = *byte as u16;
+                for _ in 0..8 {
+                    if (crc ^ data) & 1 != 0 {
+                        crc = (crc >> 1) ^ 0x8408;
+                    } else {
+                        crc >>= 1;
+                    }
+                    data >>= 1;
+                }
+            }
+            crc = !crc;
+            (crc << 8) | ((crc >> 8) & 0xFF)
+        }
+    }
+}
 
+fn encode_packet(cmd: BootloaderCommand, data: Option<&[u8]>, checksum_type: &ChecksumType) -> Vec<u8> {
+    let mut packet = Vec::new();
+    packet.push(0x01);
+    packet.push(cmd.into());
+    if let Some(data) = data {
+        let len = data.len() as u16;
+        packet.push(len as u8);
+        packet.push((len >> 8) as u8);
+        packet.extend_from_slice(data);
+    } else {
+        packet.push(0x00);
+        packet.push(0x00);
+    }
+    let checksum = checksum(&packet, checksum_type);
+    packet.push(checksum as u8);
+    packet.push((checksum >> 8) as u8);
+    packet.push(0x17);
+    packet
+}
+
+/// A byte-level link to a PSoC bootloader target, responsible for framing
+/// commands and responses. `UartTransport` reproduces the Cypress UART
+/// framing. The row programming and verification steps are generic over this
+/// trait, but the public `bootload*` and `app_status` functions always wrap
+/// their `Connection` in a `UartTransport`; none of them accepts another
+/// `Transport` implementation.
+pub trait Transport {
+    fn send_command(&mut self, cmd: BootloaderCommand, data: Option<&[u8]>, checksum_type: &ChecksumType) -> Result<(), Error>;
+    fn recv_response(&mut self, checksum_type: &ChecksumType) -> Result<Vec<u8>, Error>;
+
+    /// Discards any stale bytes left over from a previous session. A no-op
+    /// unless a transport overrides it. Implementations that do override this
+    /// must read from a source with a bounded timeout (or non-blocking reads)
+    /// — this is called before the link is known to have any data pending.
+    fn flush_input(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn command(&mut self, cmd: BootloaderCommand, data: Option<&[u8]>, checksum_type: &ChecksumType, response: bool) -> Result<Vec<u8>, Error> {
+        self.send_command(cmd, data, checksum_type)?;
         if response {
-            let mut header = [0u8; 4];
-            self.read_exact(&mut header)?;
+            self.recv_response(checksum_type)
+        } else {
+            Ok(Vec::new())
+        }
+    }
+}
 
-            if header[0] != 0x01 {
-                return Err(Error::Bootloader(BootloaderError::Data));
-            }
+/// The default [`Transport`]: today's `0x01`-start/`0x17`-end UART framing
+/// over any `Read + Write` connection.
+pub struct UartTransport<T> {
+    inner: T,
+}
 
-            if header[1] != 0x00 {
-                return Err(Error::Bootloader(BootloaderError::from(header[1])));
-            }
+impl<T> UartTransport<T> {
+    pub fn new(inner: T) -> UartTransport<T> {
+        UartTransport { inner }
+    }
 
-            let len = (header[2] as u16) | ((header[3] as u16) << 8);
-            let mut rx_data = Vec::new();
-            Read::by_ref(self).take(len as u64).read_to_end(&mut rx_data)?;
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
 
-            let mut footer = [0u8; 3];
-            self.read_exact(&mut footer);
+impl<T> Transport for UartTransport<T>
+where
+    T: Read + Write,
+{
+    fn send_command(&mut self, cmd: BootloaderCommand, data: Option<&[u8]>, checksum_type: &ChecksumType) -> Result<(), Error> {
+        let packet = encode_packet(cmd, data, checksum_type);
+        self.inner.write_all(&packet)?;
+        Ok(())
+    }
 
-            let checksum: u16 = header.iter().chain(rx_data.iter()).fold(0u16, |a,b| a+(*b as u16));
-            let checksum = 1 + !checksum;
-            let packet_checksum = (footer[0] as u16) | ((footer[1] as u16) << 8);
+    fn recv_response(&mut self, checksum_type: &ChecksumType) -> Result<Vec<u8>, Error> {
+        let mut header = [0u8; 4];
+        self.inner.read_exact(&mut header)?;
 
-            if packet_checksum != checksum {
-                return Err(Error::Bootloader(BootloaderError::Checksum));
-            }
+        if header[0] != 0x01 {
+            return Err(Error::Bootloader(BootloaderError::Data));
+        }
 
-            if footer[2] != 0x17 {
-                return Err(Error::Bootloader(BootloaderError::Data));
-            }
+        if header[1] != 0x00 {
+            return Err(Error::Bootloader(BootloaderError::from(header[1])));
+        }
 
-            Ok(rx_data)
-        } else {
-            Ok(Vec::new())
+        let len = (header[2] as u16) | ((header[3] as u16) << 8);
+        let mut rx_data = Vec::new();
+        Read::by_ref(&mut self.inner).take(len as u64).read_to_end(&mut rx_data)?;
+
+        let mut footer = [0u8; 3];
+        self.inner.read_exact(&mut footer)?;
+
+        let expected_checksum: u16 = {
+            let span: Vec<u8> = header.iter().chain(rx_data.iter()).cloned().collect();
+            checksum(&span, checksum_type)
+        };
+        let packet_checksum = (footer[0] as u16) | ((footer[1] as u16) << 8);
+
+        if packet_checksum != expected_checksum {
+            return Err(Error::Bootloader(BootloaderError::Checksum));
         }
-    }
 
-    fn create_packet(cmd: BootloaderCommand, data: Option<&[u8]>) -> Vec<u8> {
-        let mut packet = Vec::new();
-        packet.push(0x01);
-        packet.push(cmd.into());
-        if let Some(data) = data {
-            let len = data.len() as u16;
-            packet.push(len as u8);
-            packet.push((len >> 8) as u8);
-            packet.extend_from_slice(data);
-        } else {
-            packet.push(0x00);
-            packet.push(0x00);
+        if footer[2] != 0x17 {
+            return Err(Error::Bootloader(BootloaderError::Data));
         }
-        let checksum: u16 = packet.iter().fold(0u16, |a,b| a+(*b as u16));
-        let checksum = 1 + !checksum;
-        packet.push(checksum as u8);
-        packet.push((checksum >> 8) as u8);
-        packet.push(0x17);
-        packet
+
+        Ok(rx_data)
     }
 
-    fn start_bootloader(&mut self, header: &CyacdHeader) -> Result<(), Error> {
-        let packet = Self::create_packet(BootloaderCommand::EnterBootloader, None);
-        let mut res = self.transmit(&packet, true)?;
+    fn flush_input(&mut self) -> Result<(), Error> {
+        // Relies on the inner connection having a bounded read timeout (see
+        // `Connection::set_timeout`): a reader that blocks indefinitely with
+        // no data pending would hang here on the first iteration.
+        const MAX_DRAIN_READS: u32 = 16;
+        let mut discard = [0u8; 64];
+        for _ in 0..MAX_DRAIN_READS {
+            match self.inner.read(&mut discard) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => continue,
+            }
+        }
         Ok(())
     }
+}
+
+trait Bootloader: Transport + Sized {
+    fn start_bootloader(&mut self, header: &CyacdHeader, sync_config: &SyncConfig) -> Result<(), Error> {
+        // The target may still be booting or have stale bytes sitting in its
+        // receive buffer from a previous session, so flush them before syncing up.
+        self.flush_input()?;
+        self.command(BootloaderCommand::Sync, None, &header.checksum_type, false)?;
+
+        let mut last_error = Error::Host(HostError::Bootloader);
+        for _ in 0..sync_config.attempts {
+            match self.command(BootloaderCommand::EnterBootloader, None, &header.checksum_type, true) {
+                Ok(res) => {
+                    if res.len() < 8 {
+                        return Err(Error::Host(HostError::Length));
+                    }
+
+                    let silicon_id = (res[0] as u32) | (res[1] as u32) << 8 | (res[2] as u32) << 16 |
+                        (res[3] as u32) << 24;
+                    let silicon_rev = res[4];
+
+                    if silicon_id != header.silicon_id || silicon_rev != header.silicon_rev {
+                        return Err(Error::Host(HostError::Version));
+                    }
+
+                    return Ok(());
+                }
+                Err(Error::Host(HostError::Device(io_error))) if io_error.kind() == io::ErrorKind::TimedOut => {
+                    last_error = Error::Host(HostError::Device(io_error));
+                }
+                Err(error @ Error::Bootloader(BootloaderError::Data)) => {
+                    last_error = error;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        Err(last_error)
+    }
 
-    fn stop_bootloader(&mut self) -> Result<(), Error> {
-        let packet = Self::create_packet(BootloaderCommand::ExitBootloader, None);
-        let mut res = self.transmit(&packet, false)?;
+    fn stop_bootloader(&mut self, checksum_type: &ChecksumType) -> Result<(), Error> {
+        self.command(BootloaderCommand::ExitBootloader, None, checksum_type, false)?;
         Ok(())
     }
 
-    fn program_row(&mut self, row: &FlashRow) -> Result<(), Error> {
+    fn program_row(&mut self, row: &FlashRow, checksum_type: &ChecksumType) -> Result<(), Error> {
         let max_size = 50;
         let mut offset = 0;
         while row.data[offset..].len() > max_size {
-            let start = offset as usize;
-            let packet = Self::create_packet(BootloaderCommand::SendData, Some(&row.data[(offset as usize)..(offset as usize + max_size)]));
-            self.transmit(&packet, true)?;
+            self.command(
+                BootloaderCommand::SendData,
+                Some(&row.data[(offset as usize)..(offset as usize + max_size)]),
+                checksum_type,
+                true,
+            )?;
             offset += max_size;
         }
 
         let mut data = vec![row.array_id, row.row_num as u8, (row.row_num >> 8) as u8];
         data.extend_from_slice(&row.data[(offset as usize)..]);
-        let packet = Self::create_packet(BootloaderCommand::ProgramRow, Some(&data));
-        let mut res = self.transmit(packet.as_slice(), true)?;
+        self.command(BootloaderCommand::ProgramRow, Some(&data), checksum_type, true)?;
 
         Ok(())
     }
 
-    fn verify_row(&mut self, row: &FlashRow) -> Result<(), Error> {
-        let mut data = vec![row.array_id, row.row_num as u8, (row.row_num >> 8) as u8];
-        let packet = Self::create_packet(BootloaderCommand::VerifyRow, Some(&data));
-        let mut res = self.transmit(packet.as_slice(), true)?;
+    fn verify_row(&mut self, row: &FlashRow, checksum_type: &ChecksumType) -> Result<(), Error> {
+        let data = vec![row.array_id, row.row_num as u8, (row.row_num >> 8) as u8];
+        let res = self.command(BootloaderCommand::VerifyRow, Some(&data), checksum_type, true)?;
+
+        if res.is_empty() {
+            return Err(Error::Host(HostError::Length));
+        }
+
+        // The device's VerifyRow checksum excludes the framing bytes: it's a
+        // plain 8-bit sum of the row data folded together with the array_id
+        // and row_num, not the whole-record checksum from the .cyacd file.
+        let data_checksum: u8 = row.data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let expected_checksum = data_checksum
+            .wrapping_add(row.array_id)
+            .wrapping_add(row.row_num as u8)
+            .wrapping_add((row.row_num >> 8) as u8);
+
+        if res[0] != expected_checksum {
+            return Err(Error::Bootloader(BootloaderError::Checksum));
+        }
+
+        Ok(())
+    }
+
+    fn get_flash_size(&mut self, array_id: u8, checksum_type: &ChecksumType) -> Result<(u16, u16), Error> {
+        let data = vec![array_id];
+        let res = self.command(BootloaderCommand::GetFlashSize, Some(&data), checksum_type, true)?;
+
+        if res.len() < 4 {
+            return Err(Error::Host(HostError::Length));
+        }
+
+        let start_row = (res[0] as u16) | ((res[1] as u16) << 8);
+        let end_row = (res[2] as u16) | ((res[3] as u16) << 8);
+
+        Ok((start_row, end_row))
+    }
+
+    fn verify_checksum(&mut self, checksum_type: &ChecksumType) -> Result<(), Error> {
+        let res = self.command(BootloaderCommand::VerifyChecksum, None, checksum_type, true)?;
+
+        if res.is_empty() {
+            return Err(Error::Host(HostError::Length));
+        }
+
+        if res[0] == 0 {
+            return Err(Error::Bootloader(BootloaderError::Checksum));
+        }
+
+        Ok(())
+    }
+
+    fn get_app_status(&mut self, app_id: u8, checksum_type: &ChecksumType) -> Result<AppStatus, Error> {
+        let data = vec![app_id];
+        let res = self.command(BootloaderCommand::GetAppStatus, Some(&data), checksum_type, true)?;
+
+        if res.len() < 2 {
+            return Err(Error::Host(HostError::Length));
+        }
+
+        Ok(AppStatus {
+            valid: res[0] != 0,
+            active: res[1] != 0,
+        })
+    }
+
+    fn set_active_app(&mut self, app_id: u8, checksum_type: &ChecksumType) -> Result<(), Error> {
+        let data = vec![app_id];
+        self.command(BootloaderCommand::SetActiveApp, Some(&data), checksum_type, true)?;
         Ok(())
     }
 }
 
 impl<T> Bootloader for T
 where
-    T: Read + Write,
+    T: Transport,
 {
 }
 
 pub trait Connection: Read + Write {
     fn open(&mut self) -> bool;
     fn close(&mut self) -> bool;
+    fn set_timeout(&mut self, timeout: Duration) -> bool;
 }
 
 #[derive(Debug)]
@@ -211,7 +401,7 @@ impl From<io::Error> for Error {
     }
 }
 
-enum ChecksumType {
+pub enum ChecksumType {
     Sum,
     Crc,
 }
@@ -230,6 +420,37 @@ struct FlashRow {
     checksum: u8,
 }
 
+/// The validity/active state of one application slot, as reported by `GetAppStatus`.
+pub struct AppStatus {
+    pub valid: bool,
+    pub active: bool,
+}
+
+/// Tunes the reset/sync handshake used to bring the target into the bootloader.
+pub struct SyncConfig {
+    /// Number of `EnterBootloader` attempts before giving up.
+    pub attempts: u32,
+    /// Per-attempt read timeout, applied to the connection via `Connection::set_timeout`.
+    pub timeout: Duration,
+}
+
+impl Default for SyncConfig {
+    fn default() -> SyncConfig {
+        SyncConfig {
+            attempts: 5,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+fn apply_timeout<C: Connection>(comm: &mut C, timeout: Duration) -> Result<(), Error> {
+    if comm.set_timeout(timeout) {
+        Ok(())
+    } else {
+        Err(Error::Host(HostError::Device(io::Error::other("failed to configure connection timeout"))))
+    }
+}
+
 fn from_ascii(input: &str) -> Vec<u8> {
     input
         .as_bytes()
@@ -300,6 +521,15 @@ where
 
     let data = bytes.as_slice()[5..((size + 5) as usize)].to_vec();
 
+    let record_checksum = bytes[..bytes.len() - 1]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    let record_checksum = 0u8.wrapping_sub(record_checksum);
+
+    if record_checksum != checksum {
+        return Err(Error::Host(HostError::Checksum));
+    }
+
     Ok(FlashRow {
         array_id,
         row_num,
@@ -309,26 +539,178 @@ where
     })
 }
 
-pub fn bootload<I, C>(input: I, mut comm: C) -> Result<(), Error>
+/// Progress events emitted by [`bootload_with_observer`] as an image is flashed.
+pub enum BootloadEvent {
+    HeaderParsed { silicon_id: u32, silicon_rev: u8 },
+    RowProgrammed { array_id: u8, row_num: u16, bytes_written: usize, total_bytes_written: usize },
+    RowVerified { array_id: u8, row_num: u16, bytes_written: usize, total_bytes_written: usize },
+    Complete { total_bytes_written: usize },
+}
+
+/// Receives [`BootloadEvent`]s as [`bootload_with_observer`] makes progress.
+pub trait BootloadObserver {
+    fn on_event(&mut self, event: BootloadEvent);
+}
+
+impl<F> BootloadObserver for F
+where
+    F: FnMut(BootloadEvent),
+{
+    fn on_event(&mut self, event: BootloadEvent) {
+        self(event)
+    }
+}
+
+struct NoopObserver;
+
+impl BootloadObserver for NoopObserver {
+    fn on_event(&mut self, _event: BootloadEvent) {}
+}
+
+pub fn bootload<I, C>(input: I, comm: C) -> Result<(), Error>
+where
+    I: Read,
+    C: Connection,
+{
+    bootload_impl(input, comm, NoopObserver, None, &SyncConfig::default())
+}
+
+pub fn bootload_with_observer<I, C, O>(input: I, comm: C, observer: O) -> Result<(), Error>
+where
+    I: Read,
+    C: Connection,
+    O: BootloadObserver,
+{
+    bootload_impl(input, comm, observer, None, &SyncConfig::default())
+}
+
+/// Flashes `input` and, once verified, makes `app_id` the active application.
+///
+/// Useful for dual-application devices: flash the inactive slot, verify it,
+/// then switch over only if the flash succeeded.
+pub fn bootload_app<I, C>(input: I, comm: C, app_id: u8) -> Result<(), Error>
+where
+    I: Read,
+    C: Connection,
+{
+    bootload_impl(input, comm, NoopObserver, Some(app_id), &SyncConfig::default())
+}
+
+pub fn bootload_app_with_observer<I, C, O>(
+    input: I,
+    comm: C,
+    observer: O,
+    app_id: u8,
+    sync_config: &SyncConfig,
+) -> Result<(), Error>
+where
+    I: Read,
+    C: Connection,
+    O: BootloadObserver,
+{
+    bootload_impl(input, comm, observer, Some(app_id), sync_config)
+}
+
+/// Queries the validity/active state of `app_id` without flashing anything.
+///
+/// `input` only needs to yield the `.cyacd` header line, which is used to
+/// negotiate the handshake with the target.
+pub fn app_status<I, C>(input: I, mut comm: C, app_id: u8, sync_config: &SyncConfig) -> Result<AppStatus, Error>
+where
+    I: Read,
+    C: Connection,
+{
+    let mut input = BufReader::new(input);
+    let header = parse_header(&mut input)?;
+
+    comm.open();
+    apply_timeout(&mut comm, sync_config.timeout)?;
+    let mut transport = UartTransport::new(comm);
+    transport.start_bootloader(&header, sync_config)?;
+    let status = transport.get_app_status(app_id, &header.checksum_type)?;
+    transport.stop_bootloader(&header.checksum_type)?;
+    let mut comm = transport.into_inner();
+    comm.close();
+
+    Ok(status)
+}
+
+fn bootload_impl<I, C, O>(
+    input: I,
+    mut comm: C,
+    mut observer: O,
+    app_id: Option<u8>,
+    sync_config: &SyncConfig,
+) -> Result<(), Error>
 where
     I: Read,
     C: Connection,
+    O: BootloadObserver,
 {
     let mut input = BufReader::new(input);
 
     let header = parse_header(&mut input)?;
+    observer.on_event(BootloadEvent::HeaderParsed {
+        silicon_id: header.silicon_id,
+        silicon_rev: header.silicon_rev,
+    });
+
     comm.open();
-    comm.start_bootloader(&header)?;
+    apply_timeout(&mut comm, sync_config.timeout)?;
+    let mut transport = UartTransport::new(comm);
+    transport.start_bootloader(&header, sync_config)?;
+
+    let mut total_bytes_written = 0usize;
+    let mut flash_sizes: HashMap<u8, (u16, u16)> = HashMap::new();
 
     loop {
         match parse_row(&mut input) {
             Ok(row) => {
-                comm.program_row(&row)?;
-                comm.verify_row(&row)?;
+                let flash_size = match flash_sizes.get(&row.array_id) {
+                    Some(&flash_size) => flash_size,
+                    None => {
+                        let flash_size = match transport.get_flash_size(row.array_id, &header.checksum_type) {
+                            Ok(flash_size) => flash_size,
+                            Err(Error::Bootloader(BootloaderError::Array)) => {
+                                return Err(Error::Host(HostError::Array));
+                            }
+                            Err(error) => return Err(error),
+                        };
+                        flash_sizes.insert(row.array_id, flash_size);
+                        flash_size
+                    }
+                };
+
+                if row.row_num < flash_size.0 || row.row_num > flash_size.1 {
+                    return Err(Error::Host(HostError::Row));
+                }
+
+                transport.program_row(&row, &header.checksum_type)?;
+                total_bytes_written += row.data.len();
+                observer.on_event(BootloadEvent::RowProgrammed {
+                    array_id: row.array_id,
+                    row_num: row.row_num,
+                    bytes_written: row.data.len(),
+                    total_bytes_written,
+                });
+
+                transport.verify_row(&row, &header.checksum_type)?;
+                observer.on_event(BootloadEvent::RowVerified {
+                    array_id: row.array_id,
+                    row_num: row.row_num,
+                    bytes_written: row.data.len(),
+                    total_bytes_written,
+                });
             }
             Err(Error::Host(HostError::Eof)) => {
-                comm.stop_bootloader()?;
+                transport.verify_checksum(&header.checksum_type)?;
+                if let Some(app_id) = app_id {
+                    transport.set_active_app(app_id, &header.checksum_type)?;
+                }
+                transport.stop_bootloader(&header.checksum_type)?;
+                let mut comm = transport.into_inner();
                 comm.close();
+                observer.on_event(BootloadEvent::Complete { total_bytes_written });
                 return Ok(());
             }
             Err(error) => {
@@ -368,6 +750,14 @@ mod tests {
             mem::replace(&mut self.device, None);
             true
         }
+
+        fn set_timeout(&mut self, timeout: Duration) -> bool {
+            if let Some(ref mut device) = self.device {
+                device.set_timeout(timeout).is_ok()
+            } else {
+                false
+            }
+        }
     }
 
     impl Read for Comm {